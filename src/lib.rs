@@ -58,6 +58,11 @@ macro_rules! trace_deactivate {
 }
 
 /// opens a new trace file with the name <pid>.trace in the dir specified.
+/// Events are buffered per thread and only serialized to the file when a
+/// thread's buffer fills or the file is closed. An optional second argument
+/// sets that per-thread buffer capacity (defaults to 10000 events). An
+/// optional third argument names the process in the viewer via a
+/// `process_name` metadata event.
 /// # Examples
 ///
 /// ```
@@ -78,6 +83,44 @@ macro_rules! open_trace_file {
     ($dir:expr) => {
         trace_to_file_internal!($dir)
     };
+    ($dir:expr, $capacity:expr) => {
+        trace_to_file_internal!($dir, $capacity)
+    };
+    ($dir:expr, $capacity:expr, $process_name:expr) => {
+        trace_to_file_internal!($dir, $capacity, $process_name)
+    };
+}
+
+/// Route events into the Linux kernel tracing buffer via `trace_marker`,
+/// so rs_tracing spans line up with kernel events in a single timeline the
+/// way crosvm's `trace_marker` backend does.
+///
+/// Opens `/sys/kernel/tracing/trace_marker`, falling back to
+/// `/sys/kernel/debug/tracing/trace_marker`. While active every event except
+/// metadata events is written to it as a short ASCII marker line instead of
+/// JSON; metadata events are not written to it. The backend is
+/// selectable independently of the file backend; `close_trace_file!` flushes
+/// and closes whichever are open.
+/// # Examples
+///
+/// ```no_run
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// {
+/// open_trace_marker!().unwrap();
+/// {
+///     trace_scoped!("event name");
+///     println!("this is timed");
+/// }
+/// close_trace_file!();
+/// }
+/// # }
+/// ```
+#[macro_export]
+macro_rules! open_trace_marker {
+    () => {
+        trace_marker_internal!()
+    };
 }
 
 /// closes trace file
@@ -123,11 +166,57 @@ macro_rules! trace_scoped {
     ($name: expr) => {
         trace_scoped_internal!($name)
     };
+    (cat: $cat: expr, $name: expr) => {
+        trace_scoped_internal!(cat: $cat, $name)
+    };
+    (cat: $cat: expr, $name: expr, $($json:tt)+) =>{
+        trace_scoped_internal!(cat: $cat, $name, $($json)+)
+    };
     ($name: expr, $($json:tt)+) =>{
         trace_scoped_internal!($name, $($json)+)
     }
 }
 
+/// Enable a trace category so that events tagged with it are recorded.
+/// Categories are disabled until enabled, matching Fuchsia's
+/// `category_enabled`.
+///
+/// $cat: the category to enable.
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// trace_enable_category!("gpu");
+/// trace_scoped!(cat: "gpu", "event name");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_enable_category {
+    ($cat: expr) => {
+        trace_enable_category_internal!($cat)
+    };
+}
+
+/// Disable a trace category so that events tagged with it are skipped before
+/// the event is built.
+///
+/// $cat: the category to disable.
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// trace_disable_category!("gpu");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_disable_category {
+    ($cat: expr) => {
+        trace_disable_category_internal!($cat)
+    };
+}
+
 /// trace time used for expression to finish.
 /// The event type is [Complete Event (X)] with start time and duration.
 ///
@@ -160,11 +249,195 @@ macro_rules! trace_expr {
     ($name: expr, $expr: expr) => {
         trace_expr_internal!($name, $expr)
     };
+    (cat: $cat: expr, $name: expr, $expr: expr) => {
+        trace_expr_internal!(cat: $cat, $name, $expr)
+    };
+    (cat: $cat: expr, $name: expr, $expr: expr, $($json:tt)+) =>{
+        trace_expr_internal!(cat: $cat, $name, $expr, $($json)+)
+    };
     ($name: expr, $expr: expr, $($json:tt)+) =>{
         trace_expr_internal!($name, $expr, $($json)+)
     }
 }
 
+/// Mark an instant in time, an event with no duration.
+/// The event type is [Instant Event (i)] with an instant time.
+/// Instant events are category-exempt and gate only on [is_trace_active];
+/// they cannot be tagged with a `cat:` category.
+///
+/// [is_trace_active]: fn.is_trace_active.html
+///
+/// $name: name of the trace event.
+///
+/// $scope: the [InstantScope] of the marker, one of `Global`, `Process` or
+/// `Thread`.
+///
+/// $json: optional custom data formated as serdes [json] macro.
+///
+/// [Instant Event (i)]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU/preview#heading=h.lenwiilchoxp
+/// [InstantScope]: enum.InstantScope.html
+/// [json]: https://docs.serde.rs/serde_json/macro.json.html
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # use rs_tracing::InstantScope;
+/// # fn main() {
+/// trace_instant!("reached checkpoint", InstantScope::Thread);
+/// # }
+/// ```
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # use rs_tracing::InstantScope;
+/// # fn main() {
+/// trace_instant!("reached checkpoint", InstantScope::Process, "custom":"data");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_instant {
+    ($name: expr, $scope: expr) => {
+        trace_instant_internal!($name, $scope)
+    };
+    ($name: expr, $scope: expr, $($json:tt)+) =>{
+        trace_instant_internal!($name, $scope, $($json)+)
+    }
+}
+
+/// Track one or more named values over time.
+/// The event type is [Counter Event (C)] with an instant time, the supplied
+/// json becomes the `args` object and each numeric member is plotted as a
+/// named series by the viewer.
+/// Counter events are category-exempt and gate only on [is_trace_active];
+/// they cannot be tagged with a `cat:` category.
+///
+/// [is_trace_active]: fn.is_trace_active.html
+///
+/// $name: name of the trace event.
+///
+/// $json: the counter series formated as serdes [json] macro.
+///
+/// [Counter Event (C)]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU/preview#heading=h.msg3086636uq
+/// [json]: https://docs.serde.rs/serde_json/macro.json.html
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// trace_counter!("memory", "allocated":1024, "free":512);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_counter {
+    ($name: expr, $($json:tt)+) =>{
+        trace_counter_internal!($name, $($json)+)
+    }
+}
+
+/// Mark the beginning of an asynchronous event, needs to be followed by a
+/// corresponding trace_async_end with the same id.
+/// The event type is [Async Event (b)] with an instant time.
+/// Unlike trace_begin/trace_end the start and end may be on different threads;
+/// the id is used by the viewer to connect and nest the two ends.
+/// Async events are category-exempt and gate only on [is_trace_active];
+/// they cannot be tagged with a `cat:` category.
+///
+/// [is_trace_active]: fn.is_trace_active.html
+///
+/// $name: name of the trace event.
+///
+/// $id: scalar id connecting the begin and end of the event.
+///
+/// $json: optional custom data formated as serdes [json] macro.
+///
+/// [Async Event (b)]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU/preview#heading=h.jh64i9l3vwa1
+/// [json]: https://docs.serde.rs/serde_json/macro.json.html
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// trace_async_begin!("request", 7);
+/// trace_async_end!("request", 7);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_async_begin {
+    ($name: expr, $id: expr) => {
+        trace_async_internal!($name, $id, $crate::EventType::AsyncBegin)
+    };
+    ($name: expr, $id: expr, $($json:tt)+) =>{
+        trace_async_internal!($name, $id, $crate::EventType::AsyncBegin, $($json)+)
+    }
+}
+
+/// Mark the end of an asynchronous event, needs to be proceeded by a
+/// corresponding trace_async_begin with the same id.
+/// The event type is [Async Event (e)] with an instant time.
+///
+/// $name: name of the trace event.
+///
+/// $id: scalar id connecting the begin and end of the event.
+///
+/// $json: optional custom data formated as serdes [json] macro.
+///
+/// [Async Event (e)]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU/preview#heading=h.jh64i9l3vwa1
+/// [json]: https://docs.serde.rs/serde_json/macro.json.html
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// trace_async_begin!("request", 7);
+/// trace_async_end!("request", 7);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_async_end {
+    ($name: expr, $id: expr) => {
+        trace_async_internal!($name, $id, $crate::EventType::AsyncEnd)
+    };
+    ($name: expr, $id: expr, $($json:tt)+) =>{
+        trace_async_internal!($name, $id, $crate::EventType::AsyncEnd, $($json)+)
+    }
+}
+
+/// Trace an asynchronous event from invocation until end of current scope.
+/// Emits an [Async Event (b)] on construction and the matching `e` event when
+/// the scope is left, keeping the id so the two ends match.
+/// Async events are category-exempt and gate only on [is_trace_active];
+/// they cannot be tagged with a `cat:` category.
+///
+/// [is_trace_active]: fn.is_trace_active.html
+///
+/// $name: name of the trace event.
+///
+/// $id: scalar id connecting the begin and end of the event.
+///
+/// $json: optional custom data formated as serdes [json] macro.
+///
+/// [Async Event (b)]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU/preview#heading=h.jh64i9l3vwa1
+/// [json]: https://docs.serde.rs/serde_json/macro.json.html
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rs_tracing;
+/// # fn main() {
+/// {
+/// trace_async_scoped!("request", 7);
+/// println!("this is timed");
+/// }
+/// # }
+/// ```
+#[macro_export]
+macro_rules! trace_async_scoped {
+    ($name: expr, $id: expr) => {
+        trace_async_scoped_internal!($name, $id)
+    };
+    ($name: expr, $id: expr, $($json:tt)+) =>{
+        trace_async_scoped_internal!($name, $id, $($json)+)
+    }
+}
+
 /// Mark beginning of event, needs to be followed by corresponding trace_end.
 /// The event type is [Duration Event (B)] with an instant time.
 /// Start and end of the event must be on the same thread.
@@ -200,6 +473,12 @@ macro_rules! trace_begin {
     ($name: expr) => {
         trace_duration_internal!($name, $crate::EventType::DurationBegin)
     };
+    (cat: $cat: expr, $name: expr) => {
+        trace_duration_internal!(cat: $cat, $name, $crate::EventType::DurationBegin)
+    };
+    (cat: $cat: expr, $name: expr, $($json:tt)+) =>{
+        trace_duration_internal!(cat: $cat, $name, $crate::EventType::DurationBegin, $($json)+)
+    };
     ($name: expr, $($json:tt)+) =>{
         trace_duration_internal!($name, $crate::EventType::DurationBegin, $($json)+)
     }
@@ -240,6 +519,12 @@ macro_rules! trace_end {
     ($name: expr) => {
         trace_duration_internal!($name, $crate::EventType::DurationEnd)
     };
+    (cat: $cat: expr, $name: expr) => {
+        trace_duration_internal!(cat: $cat, $name, $crate::EventType::DurationEnd)
+    };
+    (cat: $cat: expr, $name: expr, $($json:tt)+) =>{
+        trace_duration_internal!(cat: $cat, $name, $crate::EventType::DurationEnd, $($json)+)
+    };
     ($name: expr, $($json:tt)+) =>{
         trace_duration_internal!($name, $crate::EventType::DurationEnd, $($json)+)
     }
@@ -256,26 +541,149 @@ mod internal {
     use std::thread::{self, ThreadId};
     use time;
 
+    use std::borrow::Cow;
+    use std::cell::Cell;
+    use std::collections::HashSet;
     use std::fs::{DirBuilder, File};
     use std::path::{Path, PathBuf};
-    use std::sync::Mutex;
+    use std::sync::{Arc, Mutex, Once};
 
     pub enum TraceState {
         InActive,
         Active,
     }
 
+    /// Number of events each thread buffers before it is flushed to the trace
+    /// file. Keeps long runs from growing memory without limit.
+    const DEFAULT_SAMPLE_CAPACITY: usize = 10_000;
+
+    type ThreadBuffer = Arc<Mutex<Vec<TraceEvent>>>;
+
+    /// Candidate paths for the kernel `trace_marker` interface, tried in
+    /// order when opening the ftrace backend.
+    const TRACE_MARKER_PATHS: [&'static str; 2] = [
+        "/sys/kernel/tracing/trace_marker",
+        "/sys/kernel/debug/tracing/trace_marker",
+    ];
+
     pub static mut TRACER: Option<Mutex<BufWriter<File>>> = None;
+    // Kernel trace_marker backend, open independently of TRACER.
+    pub static mut TRACE_MARKER: Option<Mutex<File>> = None;
     pub static mut TRACE_STATE: &'static TraceState = &TraceState::Active;
+    pub static mut ENABLED_CATEGORIES: Option<Mutex<HashSet<&'static str>>> = None;
+    pub static mut SAMPLE_CAPACITY: usize = DEFAULT_SAMPLE_CAPACITY;
+    // All per-thread buffers, so close_trace_file_fn can walk them on shutdown.
+    static mut THREAD_BUFFERS: Option<Mutex<Vec<ThreadBuffer>>> = None;
+    static THREAD_BUFFERS_INIT: Once = Once::new();
+    static ENABLED_CATEGORIES_INIT: Once = Once::new();
 
-    pub fn trace(event: &TraceEvent) {
+    thread_local! {
+        static THREAD_BUFFER: ThreadBuffer = {
+            let buffer = Arc::new(Mutex::new(Vec::new()));
+            register_thread_buffer(Arc::clone(&buffer));
+            buffer
+        };
+        // Whether this thread has already emitted its thread_name metadata.
+        static THREAD_NAMED: Cell<bool> = Cell::new(false);
+    }
+
+    fn metadata_event(name: &'static str, value: Cow<'static, str>) -> TraceEvent {
+        let mut args = serde_json::Map::new();
+        args.insert("name".to_owned(), serde_json::Value::String(value.into_owned()));
+        TraceEvent::new(name, EventType::Metadata, Some(serde_json::Value::Object(args)), None)
+    }
+
+    #[doc(hidden)]
+    pub fn default_sample_capacity() -> usize {
+        DEFAULT_SAMPLE_CAPACITY
+    }
+
+    fn register_thread_buffer(buffer: ThreadBuffer) {
+        unsafe {
+            THREAD_BUFFERS_INIT.call_once(|| {
+                THREAD_BUFFERS = Some(Mutex::new(Vec::new()));
+            });
+            if let Some(ref buffers) = THREAD_BUFFERS {
+                buffers.lock().unwrap().push(buffer);
+            }
+        }
+    }
+
+    fn write_event(file: &mut BufWriter<File>, event: &TraceEvent) {
+        serde_json::to_writer(&mut *file, event).unwrap();
+        file.write_all(b",\n").unwrap();
+    }
+
+    // Write out and clear a thread's buffer, keeping it bounded. Callers only
+    // buffer while the file backend is open, so in the common path every event
+    // reaches the file; the `TRACER == None` arm is the shutdown race where the
+    // file closed between buffering and flushing, and those few events are
+    // dropped.
+    fn flush_buffer(buffer: &mut Vec<TraceEvent>) {
         unsafe {
             if let Some(ref mut file) = TRACER {
                 let mut file = file.lock().unwrap();
-                serde_json::to_writer(&mut *file, event).unwrap();
-                file.write_all(b",\n").unwrap();
+                for event in buffer.iter() {
+                    write_event(&mut *file, event);
+                }
+            }
+        }
+        buffer.clear();
+    }
+
+    // Write a single event as an ftrace marker line. Only duration spans use
+    // the `B|<pid>|<name>` / `E|<pid>` syntax the kernel recognizes so they
+    // render as nested durations; those markers are a per-thread LIFO stack
+    // with no id, so async events (which may overlap or cross threads) are
+    // written as plain labeled markers carrying their id instead. Every other
+    // event becomes a plain labeled marker too. Metadata events only make
+    // sense for the JSON backend and are skipped.
+    fn write_trace_marker(marker: &Mutex<File>, event: &TraceEvent) {
+        let line = match event.ph {
+            EventType::DurationBegin => format!("B|{}|{}", event.pid, event.name),
+            EventType::DurationEnd => format!("E|{}", event.pid),
+            EventType::AsyncBegin => {
+                format!("async_begin: {} id={}", event.name, event.id.unwrap_or(0))
+            }
+            EventType::AsyncEnd => {
+                format!("async_end: {} id={}", event.name, event.id.unwrap_or(0))
+            }
+            EventType::Metadata => return,
+            _ => event.name.to_string(),
+        };
+        // Failures writing to the kernel buffer are non fatal.
+        let _ = writeln!(marker.lock().unwrap(), "{}", line);
+    }
+
+    pub fn trace(event: &TraceEvent) {
+        unsafe {
+            if let Some(ref marker) = TRACE_MARKER {
+                write_trace_marker(marker, event);
+            }
+            // The file backend buffers per thread, but only once it is open:
+            // `TRACE_STATE` defaults to active, so without this guard events
+            // would pile up before `open_trace_file!` and be dropped on
+            // overflow with no file to flush them to.
+            if TRACER.is_none() {
+                return;
             }
         }
+        THREAD_BUFFER.with(|buffer| {
+            let mut buffer = buffer.lock().unwrap();
+            // Label this thread's swim-lane the first time it traces anything.
+            THREAD_NAMED.with(|named| {
+                if !named.get() {
+                    named.set(true);
+                    if let Some(name) = thread::current().name() {
+                        buffer.push(metadata_event("thread_name", Cow::Owned(name.to_owned())));
+                    }
+                }
+            });
+            buffer.push(event.clone());
+            if buffer.len() >= unsafe { SAMPLE_CAPACITY } {
+                flush_buffer(&mut buffer);
+            }
+        });
     }
 
     pub fn set_trace_state(state: &'static TraceState) {
@@ -293,11 +701,74 @@ mod internal {
         }
     }
 
+    pub fn enable_category(cat: &'static str) {
+        unsafe {
+            ENABLED_CATEGORIES_INIT.call_once(|| {
+                ENABLED_CATEGORIES = Some(Mutex::new(HashSet::new()));
+            });
+            if let Some(ref categories) = ENABLED_CATEGORIES {
+                categories.lock().unwrap().insert(cat);
+            }
+        }
+    }
+
+    pub fn disable_category(cat: &'static str) {
+        unsafe {
+            if let Some(ref categories) = ENABLED_CATEGORIES {
+                categories.lock().unwrap().remove(cat);
+            }
+        }
+    }
+
+    pub fn is_category_enabled(cat: &'static str) -> bool {
+        unsafe {
+            if let Some(ref categories) = ENABLED_CATEGORIES {
+                return categories.lock().unwrap().contains(cat);
+            }
+            false
+        }
+    }
+
+    /// Category aware form of [is_trace_active]: an event is only recorded when
+    /// tracing is active and its category has been enabled.
+    pub fn is_trace_active_category(cat: &'static str) -> bool {
+        is_trace_active() && is_category_enabled(cat)
+    }
+
+    /// The scope of an instant event, controlling how wide its marker is
+    /// drawn in the viewer.
+    #[derive(Clone, Copy)]
+    pub enum InstantScope {
+        Global,
+        Process,
+        Thread,
+    }
+
+    impl Serialize for InstantScope {
+        #[doc(hidden)]
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match *self {
+                InstantScope::Global => serializer.serialize_unit_variant("InstantScope", 0, "g"),
+                InstantScope::Process => serializer.serialize_unit_variant("InstantScope", 1, "p"),
+                InstantScope::Thread => serializer.serialize_unit_variant("InstantScope", 2, "t"),
+            }
+        }
+    }
+
     #[doc(hidden)]
+    #[derive(Clone)]
     pub enum EventType {
         DurationBegin,
         DurationEnd,
         Complete,
+        Instant(InstantScope),
+        Counter,
+        AsyncBegin,
+        AsyncEnd,
+        Metadata,
     }
 
     impl Serialize for EventType {
@@ -310,22 +781,43 @@ mod internal {
                 EventType::DurationBegin => serializer.serialize_unit_variant("EventType", 0, "B"),
                 EventType::DurationEnd => serializer.serialize_unit_variant("EventType", 1, "E"),
                 EventType::Complete => serializer.serialize_unit_variant("EventType", 2, "X"),
+                EventType::Instant(_) => serializer.serialize_unit_variant("EventType", 3, "i"),
+                EventType::Counter => serializer.serialize_unit_variant("EventType", 4, "C"),
+                EventType::AsyncBegin => serializer.serialize_unit_variant("EventType", 5, "b"),
+                EventType::AsyncEnd => serializer.serialize_unit_variant("EventType", 6, "e"),
+                EventType::Metadata => serializer.serialize_unit_variant("EventType", 7, "M"),
             }
         }
     }
 
+    /// Source location of a macro call site. All three members are
+    /// compile-time constants, so capturing one costs no allocation; the `src`
+    /// string is only built when the event is serialized.
     #[doc(hidden)]
-    pub struct TraceEvent<'a> {
-        name: &'a str,
+    #[derive(Clone, Copy)]
+    pub struct SrcLocation {
+        pub module_path: &'static str,
+        pub file: &'static str,
+        pub line: u32,
+    }
+
+    #[doc(hidden)]
+    #[derive(Clone)]
+    pub struct TraceEvent {
+        name: Cow<'static, str>,
         ph: EventType,
         pub ts: u64,
         pid: u32,
         tid: u64,
         pub dur: Option<u64>,
+        s: Option<InstantScope>,
+        pub id: Option<u64>,
+        pub cat: Option<&'static str>,
         args: Option<serde_json::Value>,
+        src: Option<SrcLocation>,
     }
 
-    impl<'a> Serialize for TraceEvent<'a> {
+    impl Serialize for TraceEvent {
         #[doc(hidden)]
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -337,21 +829,65 @@ mod internal {
             event.serialize_field("ts", &self.ts)?;
             event.serialize_field("pid", &self.pid)?;
             event.serialize_field("tid", &self.tid)?;
+            if let Some(ref cat) = self.cat {
+                event.serialize_field("cat", &cat)?;
+            }
             if let Some(ref dur) = self.dur {
                 event.serialize_field("dur", &dur)?;
             }
-            if let Some(ref args) = self.args {
+            if let Some(ref s) = self.s {
+                event.serialize_field("s", &s)?;
+            }
+            if let Some(ref id) = self.id {
+                event.serialize_field("id", &id)?;
+            }
+            if let Some(args) = self.args_with_src() {
                 event.serialize_field("args", &args)?;
             }
             event.end()
         }
     }
 
-    impl<'a> TraceEvent<'a> {
+    impl TraceEvent {
+        // Fold the captured source location into the `args` object as an `src`
+        // member so it shows up in the event detail pane, merging it with any
+        // user-supplied json rather than replacing it. The string is built
+        // here, at serialize time, to keep the tracing hot path allocation
+        // free. Counter events plot every `args` member as a numeric series,
+        // so the textual `src` location is never added to them.
+        fn args_with_src(&self) -> Option<serde_json::Value> {
+            let src = match self.src {
+                Some(ref src) => src,
+                None => return self.args.clone(),
+            };
+            if let EventType::Counter = self.ph {
+                return self.args.clone();
+            }
+            let src = format!("{} ({}:{})", src.module_path, src.file, src.line);
+            let mut map = match self.args {
+                Some(serde_json::Value::Object(ref map)) => map.clone(),
+                _ => serde_json::Map::new(),
+            };
+            map.insert("src".to_owned(), serde_json::Value::String(src));
+            Some(serde_json::Value::Object(map))
+        }
+    }
+
+    impl TraceEvent {
         #[doc(hidden)]
-        pub fn new(name: &'a str, event_type: EventType, args: Option<serde_json::Value>) -> Self {
+        pub fn new<S: Into<Cow<'static, str>>>(
+            name: S,
+            event_type: EventType,
+            args: Option<serde_json::Value>,
+            src: Option<SrcLocation>,
+        ) -> Self {
+            let s = if let EventType::Instant(scope) = &event_type {
+                Some(*scope)
+            } else {
+                None
+            };
             TraceEvent {
-                name,
+                name: name.into(),
                 ph: event_type,
                 ts: precise_time_microsec(),
                 pid: process::id(),
@@ -360,26 +896,35 @@ mod internal {
                     transmute::<ThreadId, u64>(thread::current().id())
                 },
                 dur: None,
+                s,
+                id: None,
+                cat: None,
                 args,
+                src,
             }
         }
     }
 
     #[doc(hidden)]
-    pub struct EventGuard<'a> {
-        event: TraceEvent<'a>,
+    pub struct EventGuard {
+        event: TraceEvent,
     }
 
-    impl<'a> EventGuard<'a> {
+    impl EventGuard {
         #[doc(hidden)]
-        pub fn new(name: &'a str, args: Option<serde_json::Value>) -> EventGuard<'a> {
-            EventGuard {
-                event: TraceEvent::new(name, EventType::Complete, args),
-            }
+        pub fn new<S: Into<Cow<'static, str>>>(
+            name: S,
+            cat: Option<&'static str>,
+            args: Option<serde_json::Value>,
+            src: Option<SrcLocation>,
+        ) -> EventGuard {
+            let mut event = TraceEvent::new(name, EventType::Complete, args, src);
+            event.cat = cat;
+            EventGuard { event }
         }
     }
 
-    impl<'a> Drop for EventGuard<'a> {
+    impl Drop for EventGuard {
         #[doc(hidden)]
         fn drop(&mut self) {
             self.event.dur = Some(precise_time_microsec() - self.event.ts);
@@ -387,7 +932,45 @@ mod internal {
         }
     }
 
-    pub fn init_trace_to_file<P: AsRef<Path>>(dir: P) -> io::Result<()> {
+    #[doc(hidden)]
+    pub struct AsyncEventGuard {
+        name: Cow<'static, str>,
+        id: u64,
+    }
+
+    impl AsyncEventGuard {
+        #[doc(hidden)]
+        pub fn new<S: Into<Cow<'static, str>>>(
+            name: S,
+            id: u64,
+            args: Option<serde_json::Value>,
+            src: Option<SrcLocation>,
+        ) -> AsyncEventGuard {
+            let name = name.into();
+            let mut event = TraceEvent::new(name.clone(), EventType::AsyncBegin, args, src);
+            event.id = Some(id);
+            trace(&event);
+            AsyncEventGuard { name, id }
+        }
+    }
+
+    impl Drop for AsyncEventGuard {
+        #[doc(hidden)]
+        fn drop(&mut self) {
+            let mut event = TraceEvent::new(self.name.clone(), EventType::AsyncEnd, None, None);
+            event.id = Some(self.id);
+            trace(&event);
+        }
+    }
+
+    pub fn init_trace_to_file<P: AsRef<Path>>(
+        dir: P,
+        capacity: usize,
+        process_name: Option<&'static str>,
+    ) -> io::Result<()> {
+        unsafe {
+            SAMPLE_CAPACITY = capacity;
+        }
         let mut dir_path = PathBuf::new();
         dir_path.push(dir);
         let mut file_path = dir_path.clone();
@@ -403,16 +986,47 @@ mod internal {
         unsafe {
             TRACER = Some(file);
         }
+        if let Some(process_name) = process_name {
+            trace(&metadata_event("process_name", Cow::Borrowed(process_name)));
+        }
         Ok(())
     }
 
+    pub fn init_trace_marker() -> io::Result<()> {
+        use std::fs::OpenOptions;
+        let mut last_err = None;
+        for path in TRACE_MARKER_PATHS.iter() {
+            match OpenOptions::new().write(true).open(path) {
+                Ok(file) => {
+                    unsafe {
+                        TRACE_MARKER = Some(Mutex::new(file));
+                    }
+                    return Ok(());
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no trace_marker interface found")
+        }))
+    }
+
     pub fn close_trace_file_fn() {
         unsafe {
+            if let Some(ref buffers) = THREAD_BUFFERS {
+                for buffer in buffers.lock().unwrap().iter() {
+                    flush_buffer(&mut buffer.lock().unwrap());
+                }
+            }
             if let Some(ref mut file) = TRACER {
                 let mut file = file.lock().unwrap();
                 (*file).flush().unwrap();
             }
             TRACER = None;
+            if let Some(ref marker) = TRACE_MARKER {
+                marker.lock().unwrap().flush().unwrap();
+            }
+            TRACE_MARKER = None;
         }
     }
 
@@ -433,10 +1047,52 @@ mod internal {
     #[macro_export]
     macro_rules! trace_to_file_internal {
         ($dir:expr) => {
-            $crate::init_trace_to_file($dir)
+            $crate::init_trace_to_file($dir, $crate::default_sample_capacity(), None)
+        };
+        ($dir:expr, $capacity:expr) => {
+            $crate::init_trace_to_file($dir, $capacity, None)
+        };
+        ($dir:expr, $capacity:expr, $process_name:expr) => {
+            $crate::init_trace_to_file($dir, $capacity, Some($process_name))
         };
     }
 
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_marker_internal {
+        () => {
+            $crate::init_trace_marker()
+        };
+    }
+
+    // Whether rs_tracing was built with location capture. Evaluated here, in
+    // this crate, so the `no_trace_location` feature of rs_tracing decides it
+    // and not the features of the crate expanding `trace_src_location!`.
+    #[doc(hidden)]
+    pub fn trace_location_enabled() -> bool {
+        cfg!(not(feature = "no_trace_location"))
+    }
+
+    /// Capture the source location of the macro call site. `module_path!`,
+    /// `file!` and `line!` are compile-time constants so this is free at
+    /// runtime. Disabled by the `no_trace_location` feature for users that
+    /// want minimal output.
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_src_location {
+        () => {{
+            if $crate::trace_location_enabled() {
+                Some($crate::SrcLocation {
+                    module_path: module_path!(),
+                    file: file!(),
+                    line: line!(),
+                })
+            } else {
+                None
+            }
+        }};
+    }
+
     #[doc(hidden)]
     #[macro_export]
     macro_rules! close_trace_file_internal {
@@ -450,26 +1106,80 @@ mod internal {
     macro_rules! trace_scoped_internal {
     ($name: expr) => {
         let _guard = if $crate::is_trace_active() {
-            Some($crate::EventGuard::new($name, None))
+            Some($crate::EventGuard::new($name, None, None, trace_src_location!()))
+        }else{
+            None
+        };
+    };
+    (cat: $cat: expr, $name: expr) => {
+        let _guard = if $crate::is_trace_active_category($cat) {
+            Some($crate::EventGuard::new($name, Some($cat), None, trace_src_location!()))
+        }else{
+            None
+        };
+    };
+    (cat: $cat: expr, $name: expr, $($json:tt)+) =>{
+        let _guard = if $crate::is_trace_active_category($cat) {
+            Some($crate::EventGuard::new($name, Some($cat), Some(json!({$($json)+})), trace_src_location!()))
         }else{
             None
         };
     };
     ($name: expr, $($json:tt)+) =>{
         let _guard = if $crate::is_trace_active() {
-            Some($crate::EventGuard::new($name, Some(json!({$($json)+}))))
+            Some($crate::EventGuard::new($name, None, Some(json!({$($json)+})), trace_src_location!()))
         }else{
             None
         };
     }
 }
 
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_enable_category_internal {
+        ($cat: expr) => {
+            $crate::enable_category($cat)
+        };
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_disable_category_internal {
+        ($cat: expr) => {
+            $crate::disable_category($cat)
+        };
+    }
+
     #[doc(hidden)]
     #[macro_export]
     macro_rules! trace_expr_internal {
     ($name: expr, $expr: expr) => {
         if $crate::is_trace_active() {
-            let mut event = $crate::TraceEvent::new($name, $crate::EventType::Complete, None);
+            let mut event = $crate::TraceEvent::new($name, $crate::EventType::Complete, None, trace_src_location!());
+            let result = $expr;
+            event.dur = Some($crate::precise_time_microsec() - event.ts);
+            $crate::trace(&event);
+            result
+        }else{
+            $expr
+        }
+    };
+    (cat: $cat: expr, $name: expr, $expr: expr) => {
+        if $crate::is_trace_active_category($cat) {
+            let mut event = $crate::TraceEvent::new($name, $crate::EventType::Complete, None, trace_src_location!());
+            event.cat = Some($cat);
+            let result = $expr;
+            event.dur = Some($crate::precise_time_microsec() - event.ts);
+            $crate::trace(&event);
+            result
+        }else{
+            $expr
+        }
+    };
+    (cat: $cat: expr, $name: expr, $expr: expr, $($json:tt)+) =>{
+        if $crate::is_trace_active_category($cat) {
+            let mut event = $crate::TraceEvent::new($name, $crate::EventType::Complete, Some(json!({$($json)+})), trace_src_location!());
+            event.cat = Some($cat);
             let result = $expr;
             event.dur = Some($crate::precise_time_microsec() - event.ts);
             $crate::trace(&event);
@@ -480,7 +1190,7 @@ mod internal {
     };
     ($name: expr, $expr: expr, $($json:tt)+) =>{
         if $crate::is_trace_active() {
-            let mut event = $crate::TraceEvent::new($name, $crate::EventType::Complete, Some(json!({$($json)+})));
+            let mut event = $crate::TraceEvent::new($name, $crate::EventType::Complete, Some(json!({$($json)+})), trace_src_location!());
             let result = $expr;
             event.dur = Some($crate::precise_time_microsec() - event.ts);
             $crate::trace(&event);
@@ -496,15 +1206,102 @@ mod internal {
     macro_rules! trace_duration_internal {
     ($name: expr, $event_type: expr) => {
         if $crate::is_trace_active() {
-            let event = $crate::TraceEvent::new($name, $event_type, None);
+            let event = $crate::TraceEvent::new($name, $event_type, None, trace_src_location!());
+            $crate::trace(&event);
+        }
+    };
+    (cat: $cat: expr, $name: expr, $event_type: expr) => {
+        if $crate::is_trace_active_category($cat) {
+            let mut event = $crate::TraceEvent::new($name, $event_type, None, trace_src_location!());
+            event.cat = Some($cat);
+            $crate::trace(&event);
+        }
+    };
+    (cat: $cat: expr, $name: expr, $event_type: expr, $($json:tt)+) =>{
+        if $crate::is_trace_active_category($cat) {
+            let mut event = $crate::TraceEvent::new($name, $event_type, Some(json!({$($json)+})), trace_src_location!());
+            event.cat = Some($cat);
             $crate::trace(&event);
         }
     };
     ($name: expr, $event_type: expr, $($json:tt)+) =>{
         if $crate::is_trace_active() {
-            let event = $crate::TraceEvent::new($name, $event_type, Some(json!({$($json)+})));
+            let event = $crate::TraceEvent::new($name, $event_type, Some(json!({$($json)+})), trace_src_location!());
+            $crate::trace(&event);
+        }
+    }
+}
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_instant_internal {
+    ($name: expr, $scope: expr) => {
+        if $crate::is_trace_active() {
+            let event =
+                $crate::TraceEvent::new($name, $crate::EventType::Instant($scope), None, trace_src_location!());
             $crate::trace(&event);
         }
+    };
+    ($name: expr, $scope: expr, $($json:tt)+) =>{
+        if $crate::is_trace_active() {
+            let event = $crate::TraceEvent::new(
+                $name,
+                $crate::EventType::Instant($scope),
+                Some(json!({$($json)+})),
+                trace_src_location!(),
+            );
+            $crate::trace(&event);
+        }
+    }
+}
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_counter_internal {
+    ($name: expr, $($json:tt)+) =>{
+        if $crate::is_trace_active() {
+            let event =
+                $crate::TraceEvent::new($name, $crate::EventType::Counter, Some(json!({$($json)+})), trace_src_location!());
+            $crate::trace(&event);
+        }
+    }
+}
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_async_internal {
+    ($name: expr, $id: expr, $event_type: expr) => {
+        if $crate::is_trace_active() {
+            let mut event = $crate::TraceEvent::new($name, $event_type, None, trace_src_location!());
+            event.id = Some($id);
+            $crate::trace(&event);
+        }
+    };
+    ($name: expr, $id: expr, $event_type: expr, $($json:tt)+) =>{
+        if $crate::is_trace_active() {
+            let mut event = $crate::TraceEvent::new($name, $event_type, Some(json!({$($json)+})), trace_src_location!());
+            event.id = Some($id);
+            $crate::trace(&event);
+        }
+    }
+}
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_async_scoped_internal {
+    ($name: expr, $id: expr) => {
+        let _async_guard = if $crate::is_trace_active() {
+            Some($crate::AsyncEventGuard::new($name, $id, None, trace_src_location!()))
+        }else{
+            None
+        };
+    };
+    ($name: expr, $id: expr, $($json:tt)+) =>{
+        let _async_guard = if $crate::is_trace_active() {
+            Some($crate::AsyncEventGuard::new($name, $id, Some(json!({$($json)+})), trace_src_location!()))
+        }else{
+            None
+        };
     }
 }
 
@@ -522,6 +1319,14 @@ mod internal {
     #[macro_export]
     macro_rules! trace_to_file_internal {
         ($dir:expr) => {};
+        ($dir:expr, $capacity:expr) => {};
+        ($dir:expr, $capacity:expr, $process_name:expr) => {};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_marker_internal {
+        () => {};
     }
 
     #[doc(hidden)]
@@ -542,14 +1347,56 @@ mod internal {
         ($name:expr, $expr:expr) => {
             $expr
         };
+        (cat: $cat:expr, $name:expr, $expr:expr) => {
+            $expr
+        };
+        (cat: $cat:expr, $name:expr, $expr:expr, $($json:tt)+) => {
+            $expr
+        };
         ($name:expr, $expr:expr, $($json:tt)+) => {
             $expr
         };
     }
 
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_enable_category_internal {
+        ($($some:tt)+) => {};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_disable_category_internal {
+        ($($some:tt)+) => {};
+    }
+
     #[doc(hidden)]
     #[macro_export]
     macro_rules! trace_duration_internal {
         ($($some:tt)+) => {};
     }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_instant_internal {
+        ($($some:tt)+) => {};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_counter_internal {
+        ($($some:tt)+) => {};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_async_internal {
+        ($($some:tt)+) => {};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! trace_async_scoped_internal {
+        ($($some:tt)+) => {};
+    }
 } // mod internal